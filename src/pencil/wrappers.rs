@@ -2,30 +2,395 @@
 // Copyright (c) 2014 by Shipeng Feng.
 // Licensed under the BSD License, see LICENSE for more details.
 
-use std::io::net::ip::SocketAddr;
+use std::io::net::ip::{SocketAddr, IpAddr};
 
 use http;
 use http::server::request::RequestUri::AbsolutePath;
 use http::headers::request::HeaderCollection;
 use http::headers::HeaderConvertible;
 use url;
-use url::form_urlencoded::parse as form_urlencoded_parse;
+use serialize::json::Json;
 
+use config::Config;
 use datastructures::{Headers, MultiDict};
 use httputils::{get_name_by_http_code, get_content_type};
 
 
+/// Represents an uploaded file contained in a `multipart/form-data`
+/// request body.  `data` is only a faithful copy for UTF-8-safe
+/// content: `http::server::Request::body` is a `String`, so invalid
+/// UTF-8 in a binary upload has already been lossily replaced upstream.
+#[deriving(Clone)]
+pub struct FileStorage {
+    pub filename: Option<String>,
+    pub content_type: Option<String>,
+    pub data: Vec<u8>,
+}
+
+
+/// Removes `\r` and `\n` from a header value to prevent HTTP response
+/// splitting.
+fn strip_crlf(value: &str) -> String {
+    value.chars().filter(|&c| c != '\r' && c != '\n').collect()
+}
+
+
+/// Removes `\r`, `\n`, `;` and `=` from a `Set-Cookie` attribute, so a
+/// caller-supplied `name`/`domain`/`path`/`same_site` can't smuggle
+/// extra attributes into the header line.
+fn strip_cookie_attr_chars(value: &str) -> String {
+    value.chars().filter(|&c| c != '\r' && c != '\n' && c != ';' && c != '=').collect()
+}
+
+
+/// Looks up a header in `HeaderCollection::extensions` by name,
+/// case-insensitively.  Headers that `http::headers::request` doesn't
+/// know about (`Cookie`, `X-Forwarded-*`, ...) land in `extensions`
+/// keyed however they arrived on the wire, and HTTP header names are
+/// case-insensitive, so an exact-case lookup would silently miss a
+/// lowercase `cookie:` or `x-forwarded-for:` sent by a real client or
+/// proxy.
+fn get_extension_header<'a>(headers: &'a HeaderCollection, name: &str) -> Option<&'a str> {
+    for (key, value) in headers.extensions.iter() {
+        if key.as_slice().eq_ignore_ascii_case(name) {
+            return Some(value.as_slice());
+        }
+    }
+    None
+}
+
+
+/// Looks up a parameter (e.g. `boundary` or `charset`) on a `Content-Type`
+/// header value.
+fn get_content_type_param(content_type: &http::headers::content_type::MediaType, name: &str) -> Option<String> {
+    for &(ref key, ref value) in content_type.parameters.iter() {
+        if key.as_slice().eq_ignore_ascii_case(name) {
+            return Some(value.clone());
+        }
+    }
+    None
+}
+
+
+/// Splits a header value on `;` the way `Content-Disposition` parameters
+/// require: a `;` inside a `"..."` quoted string is part of the value,
+/// not a separator, so e.g. `filename="a;b.txt"` stays intact.
+fn split_header_params(header_value: &str) -> Vec<String> {
+    let mut segments = Vec::new();
+    let mut current = String::new();
+    let mut in_quotes = false;
+    for c in header_value.chars() {
+        if c == '"' {
+            in_quotes = !in_quotes;
+            current.push(c);
+        } else if c == ';' && !in_quotes {
+            segments.push(current);
+            current = String::new();
+        } else {
+            current.push(c);
+        }
+    }
+    segments.push(current);
+    segments
+}
+
+
+/// Extracts a `key="value"` (or unquoted `key=value`) parameter from a
+/// header value such as a `Content-Disposition` line.  Quoted values may
+/// contain `;` themselves, so the header is split with
+/// `split_header_params` rather than a plain `str::split(';')`.
+fn extract_header_param(header_value: &str, param: &str) -> Option<String> {
+    for segment in split_header_params(header_value).iter() {
+        let segment = segment.as_slice().trim();
+        match segment.find('=') {
+            Some(pos) => {
+                let key = segment.slice_to(pos).trim();
+                if key.eq_ignore_ascii_case(param) {
+                    let value = segment.slice_from(pos + 1).trim();
+                    return Some(value.trim_chars('"').to_string());
+                }
+            },
+            None => (),
+        }
+    }
+    None
+}
+
+
+/// Percent- and plus-decodes a `application/x-www-form-urlencoded`
+/// component into its raw bytes, without assuming any particular
+/// charset.
+fn percent_decode(input: &str) -> Vec<u8> {
+    let bytes = input.as_bytes();
+    let mut output = Vec::with_capacity(bytes.len());
+    let mut i = 0u;
+    while i < bytes.len() {
+        let byte = bytes[i];
+        if byte == b'+' {
+            output.push(b' ');
+            i += 1;
+        } else if byte == b'%' && i + 2 < bytes.len() {
+            let hi = (bytes[i + 1] as char).to_digit(16);
+            let lo = (bytes[i + 2] as char).to_digit(16);
+            match (hi, lo) {
+                (Some(hi), Some(lo)) => {
+                    output.push(((hi << 4) | lo) as u8);
+                    i += 3;
+                },
+                _ => {
+                    output.push(byte);
+                    i += 1;
+                },
+            }
+        } else {
+            output.push(byte);
+            i += 1;
+        }
+    }
+    output
+}
+
+
+/// Percent-decodes a component (e.g. a `Cookie` value) into its raw
+/// bytes, without assuming any particular charset.  Unlike
+/// `percent_decode`, `+` is kept literal rather than treated as a
+/// space, matching `url::decode_component`'s semantics.
+fn percent_decode_component(input: &str) -> Vec<u8> {
+    let bytes = input.as_bytes();
+    let mut output = Vec::with_capacity(bytes.len());
+    let mut i = 0u;
+    while i < bytes.len() {
+        let byte = bytes[i];
+        if byte == b'%' && i + 2 < bytes.len() {
+            let hi = (bytes[i + 1] as char).to_digit(16);
+            let lo = (bytes[i + 2] as char).to_digit(16);
+            match (hi, lo) {
+                (Some(hi), Some(lo)) => {
+                    output.push(((hi << 4) | lo) as u8);
+                    i += 3;
+                },
+                _ => {
+                    output.push(byte);
+                    i += 1;
+                },
+            }
+        } else {
+            output.push(byte);
+            i += 1;
+        }
+    }
+    output
+}
+
+
+/// Transcodes raw bytes into a `String` according to the declared
+/// charset.  Only `utf-8` and `iso-8859-1`/`latin1` are recognised;
+/// anything else, including no charset at all, falls back to lossy
+/// UTF-8 decoding so existing callers keep seeing their previous
+/// behavior.
+fn decode_charset(bytes: &[u8], charset: &str) -> String {
+    if charset.eq_ignore_ascii_case("iso-8859-1") || charset.eq_ignore_ascii_case("latin1") {
+        bytes.iter().map(|&byte| byte as char).collect()
+    } else {
+        String::from_utf8_lossy(bytes).into_string()
+    }
+}
+
+
+/// Parses a `Cookie` header into a `MultiDict` of name/value pairs.
+/// Values are percent-decoded through the byte-safe `percent_decode_component`
+/// helper rather than `url::decode_component`, so a malformed,
+/// non-UTF-8 percent-encoded value cannot panic.
+fn parse_cookie_header(header: &str) -> MultiDict {
+    let mut cookies = MultiDict::new();
+    for pair in header.split(';') {
+        let pair = pair.trim();
+        if pair.is_empty() {
+            continue;
+        }
+        match pair.find('=') {
+            Some(pos) => {
+                let name = pair.slice_to(pos).trim();
+                let value = pair.slice_from(pos + 1).trim();
+                let decoded = decode_charset(percent_decode_component(value).as_slice(), "utf-8");
+                cookies.add(name, decoded.as_slice());
+            },
+            None => (),
+        }
+    }
+    cookies
+}
+
+
+/// Parses a `application/x-www-form-urlencoded` body or query string
+/// into `(key, value)` pairs, decoding both through the given charset.
+fn decode_www_form_urlencoded(raw: &str, charset: &str) -> Vec<(String, String)> {
+    let mut pairs = Vec::new();
+    for pair in raw.split('&') {
+        if pair.is_empty() {
+            continue;
+        }
+        let (raw_key, raw_value) = match pair.find('=') {
+            Some(pos) => (pair.slice_to(pos), pair.slice_from(pos + 1)),
+            None => (pair, ""),
+        };
+        let key = decode_charset(percent_decode(raw_key).as_slice(), charset);
+        let value = decode_charset(percent_decode(raw_value).as_slice(), charset);
+        pairs.push((key, value));
+    }
+    pairs
+}
+
+
+/// Parses `body` as JSON when `is_json_content_type` is true, returning
+/// `None` for a non-JSON content type or a malformed body.
+fn parse_json_body(is_json_content_type: bool, body: &str) -> Option<Json> {
+    if is_json_content_type {
+        from_str::<Json>(body)
+    } else {
+        None
+    }
+}
+
+
+/// Resolves a proxy-forwardable string value (scheme, host): only
+/// consults `forwarded` when `trusted_proxy` is true and a value was
+/// actually sent, otherwise keeps the direct-connection value.
+fn resolve_trusted_value(trusted_proxy: bool, forwarded: Option<&str>, direct: String) -> String {
+    resolve_trusted_optional(trusted_proxy, forwarded, Some(direct)).unwrap()
+}
+
+
+/// Like `resolve_trusted_value`, but for callers (like `host()`) whose
+/// direct-connection fallback may itself be absent.
+fn resolve_trusted_optional(trusted_proxy: bool, forwarded: Option<&str>, direct: Option<String>) -> Option<String> {
+    if trusted_proxy {
+        match forwarded {
+            Some(value) => return Some(value.trim().to_string()),
+            None => (),
+        }
+    }
+    direct
+}
+
+
+/// Resolves the client address, honouring the first entry of a trusted
+/// `X-Forwarded-For` header over the direct TCP peer.  The forwarded
+/// address carries no port, so `0` is reported in that case.
+fn resolve_trusted_remote_addr(trusted_proxy: bool, forwarded_for: Option<&str>,
+                                direct: Option<SocketAddr>) -> Option<SocketAddr> {
+    if trusted_proxy {
+        match forwarded_for {
+            Some(value) => {
+                match value.split(',').next() {
+                    Some(addr) => {
+                        match from_str::<IpAddr>(addr.trim()) {
+                            Some(ip) => return Some(SocketAddr { ip: ip, port: 0 }),
+                            None => (),
+                        }
+                    },
+                    None => (),
+                }
+            },
+            None => (),
+        }
+    }
+    direct
+}
+
+
+/// Parses a `multipart/form-data` body into the `form` and `files`
+/// collections, given the boundary extracted from the `Content-Type`
+/// header.  See `FileStorage` for the caveat on binary upload bytes:
+/// `body` has already passed through `String`, so this only sees
+/// UTF-8-safe data.
+fn parse_multipart(body: &str, boundary: &str, form: &mut MultiDict, files: &mut Vec<(String, FileStorage)>) {
+    let delimiter = String::from_str("--") + boundary;
+    for part in body.split_str(delimiter.as_slice()) {
+        // Skip the preamble and the closing `--boundary--` marker.
+        if part.starts_with("--") || part.trim().is_empty() {
+            continue;
+        }
+        let part = part.trim_left_chars('\r').trim_left_chars('\n');
+        let (header_block, body_block) = match part.find_str("\r\n\r\n") {
+            Some(pos) => (part.slice_to(pos), part.slice_from(pos + 4)),
+            None => continue,
+        };
+        let body_block = if body_block.ends_with("\r\n") {
+            body_block.slice_to(body_block.len() - 2)
+        } else {
+            body_block
+        };
+
+        let mut disposition = None;
+        let mut part_content_type = None;
+        for line in header_block.split_str("\r\n") {
+            match line.find(':') {
+                Some(pos) => {
+                    let name = line.slice_to(pos).trim();
+                    let value = line.slice_from(pos + 1).trim();
+                    if name.eq_ignore_ascii_case("Content-Disposition") {
+                        disposition = Some(value.to_string());
+                    } else if name.eq_ignore_ascii_case("Content-Type") {
+                        part_content_type = Some(value.to_string());
+                    }
+                },
+                None => (),
+            }
+        }
+
+        let disposition = match disposition {
+            Some(disposition) => disposition,
+            None => continue,
+        };
+        let field_name = match extract_header_param(disposition.as_slice(), "name") {
+            Some(name) => name,
+            None => continue,
+        };
+        // An empty `filename=""` (e.g. a browser submitting an unset
+        // file input) is not an upload; treat it like no filename at
+        // all so it lands in `form` rather than as a phantom 0-byte file.
+        let filename = match extract_header_param(disposition.as_slice(), "filename") {
+            Some(ref filename) if filename.is_empty() => None,
+            other => other,
+        };
+
+        match filename {
+            Some(filename) => {
+                files.push((field_name, FileStorage {
+                    filename: Some(filename),
+                    content_type: part_content_type,
+                    data: body_block.as_bytes().to_vec(),
+                }));
+            },
+            None => {
+                form.add(field_name.as_slice(), body_block);
+            },
+        }
+    }
+}
+
+
 /// Request type.
 pub struct Request {
     pub request: http::server::Request,
     url: Option<url::Url>,
     args: Option<MultiDict>,
     form: Option<MultiDict>,
+    files: Option<Vec<(String, FileStorage)>>,
+    cookies: Option<MultiDict>,
+    json: Option<Option<Json>>,
+    trusted_proxy: bool,
+    charset: Option<String>,
 }
 
 impl Request {
-    /// Create a `Request`.
-    pub fn new(request: http::server::Request) -> Request {
+    /// Create a `Request`.  The app's `config` is consulted for a
+    /// `"TRUSTED_PROXY"` boolean, which gates whether `scheme()`,
+    /// `host()` and `remote_addr()` honour `X-Forwarded-*` headers;
+    /// left unset (or not `true`), forwarded headers are ignored so
+    /// clients cannot spoof them.
+    pub fn new(request: http::server::Request, config: &Config) -> Request {
         let url = match request.request_uri {
             AbsolutePath(ref url) => {
                 match request.headers.host {
@@ -42,22 +407,40 @@ impl Request {
             },
             _ => None,
         };
+        let trusted_proxy = match config.get("TRUSTED_PROXY") {
+            Some(&Json::Boolean(value)) => value,
+            _ => false,
+        };
         Request {
             request: request,
             url: url,
             args: None,
             form: None,
+            files: None,
+            cookies: None,
+            json: None,
+            trusted_proxy: trusted_proxy,
+            charset: None,
         }
     }
 
-    /// The parsed URL parameters.
+    /// Overrides whether this request is treated as coming through a
+    /// trusted reverse proxy; `new()` already sets this from the app's
+    /// `"TRUSTED_PROXY"` config setting, so this is only needed to
+    /// override that resolution (e.g. in tests).
+    pub fn set_trusted_proxy(&mut self, trusted_proxy: bool) {
+        self.trusted_proxy = trusted_proxy;
+    }
+
+    /// The parsed URL parameters, decoded through `charset()`.
     pub fn args(&mut self) -> &MultiDict {
         if self.args.is_none() {
+            let charset = self.charset();
             let mut args = MultiDict::new();
             if self.url.is_some() {
-                match self.url.as_ref().unwrap().query_pairs() {
-                    Some(pairs) => {
-                        for &(ref k, ref v) in pairs.iter() {
+                match self.url.as_ref().unwrap().query {
+                    Some(ref query) => {
+                        for &(ref k, ref v) in decode_www_form_urlencoded(query.as_slice(), charset.as_slice()).iter() {
                             args.add(k.as_slice(), v.as_slice());
                         }
                     },
@@ -69,30 +452,63 @@ impl Request {
         return self.args.as_ref().unwrap();
     }
 
+    /// The charset declared by the request's `Content-Type` header
+    /// (its `charset` parameter), defaulting to `"utf-8"` when absent.
+    /// An unrecognised charset is returned as declared; decoding then
+    /// falls back to UTF-8 (see `decode_charset`).  Used to decode
+    /// `args()` and the urlencoded `form()`.
+    pub fn charset(&mut self) -> String {
+        if self.charset.is_none() {
+            let charset = match self.request.headers.content_type {
+                Some(ref content_type) => {
+                    match get_content_type_param(content_type, "charset") {
+                        Some(charset) => charset,
+                        None => String::from_str("utf-8"),
+                    }
+                },
+                None => String::from_str("utf-8"),
+            };
+            self.charset = Some(charset);
+        }
+        self.charset.as_ref().unwrap().clone()
+    }
+
+    /// Alias for `charset()`.
+    pub fn url_charset(&mut self) -> String {
+        self.charset()
+    }
+
     /// This method is used internally to retrieve submitted data.
     fn load_form_data(&mut self) {
         if self.form.is_some() {
             return
         }
-        let form = match self.request.headers.content_type {
+        let charset = self.charset();
+        let mut form = MultiDict::new();
+        let mut files = Vec::new();
+        match self.request.headers.content_type {
             Some(ref content_type) => {
                 if content_type.type_ == String::from_str("application") &&
                     (content_type.subtype == String::from_str("x-www-form-urlencoded") ||
                      content_type.subtype == String::from_str("x-url-encoded")) {
-                    let mut form = MultiDict::new();
-                    for &(ref k, ref v) in form_urlencoded_parse(self.request.body.as_slice()).iter() {
+                    for &(ref k, ref v) in decode_www_form_urlencoded(self.request.body.as_slice(), charset.as_slice()).iter() {
                         form.add(k.as_slice(), v.as_slice());
                     }
-                    form
-                } else {
-                    MultiDict::new()
+                } else if content_type.type_ == String::from_str("multipart") &&
+                    content_type.subtype == String::from_str("form-data") {
+                    match get_content_type_param(content_type, "boundary") {
+                        Some(boundary) => {
+                            parse_multipart(self.request.body.as_slice(), boundary.as_slice(),
+                                             &mut form, &mut files);
+                        },
+                        None => (),
+                    }
                 }
             },
-            None => {
-                MultiDict::new()
-            }
+            None => (),
         };
         self.form = Some(form);
+        self.files = Some(files);
     }
 
     /// The form parameters.
@@ -101,6 +517,44 @@ impl Request {
         self.form.as_ref().unwrap()
     }
 
+    /// The uploaded files, as `(field name, FileStorage)` pairs.  See
+    /// `FileStorage` for why non-UTF-8 binary uploads are not currently
+    /// preserved byte-for-byte.
+    pub fn files(&mut self) -> &Vec<(String, FileStorage)> {
+        self.load_form_data();
+        self.files.as_ref().unwrap()
+    }
+
+    /// The parsed JSON body, if the request declares a `Content-Type`
+    /// of `application/json` and the body parses successfully.  The
+    /// result is cached, so calling this repeatedly is cheap.
+    pub fn json(&mut self) -> Option<Json> {
+        if self.json.is_none() {
+            let is_json_content_type = match self.request.headers.content_type {
+                Some(ref content_type) => {
+                    content_type.type_ == String::from_str("application") &&
+                        content_type.subtype == String::from_str("json")
+                },
+                None => false,
+            };
+            let result = parse_json_body(is_json_content_type, self.request.body.as_slice());
+            self.json = Some(result);
+        }
+        self.json.as_ref().unwrap().clone()
+    }
+
+    /// The parsed cookies.
+    pub fn cookies(&mut self) -> &MultiDict {
+        if self.cookies.is_none() {
+            let cookies = match get_extension_header(&self.request.headers, "Cookie") {
+                Some(cookie_header) => parse_cookie_header(cookie_header),
+                None => MultiDict::new(),
+            };
+            self.cookies = Some(cookies);
+        }
+        return self.cookies.as_ref().unwrap();
+    }
+
     /// The headers.
     pub fn headers(&self) -> &HeaderCollection {
         &self.request.headers
@@ -126,12 +580,16 @@ impl Request {
         }
     }
 
-    /// The host including the port if available.
+    /// The host including the port if available.  Behind a trusted
+    /// proxy (see `set_trusted_proxy`), the `X-Forwarded-Host` header
+    /// takes precedence over the directly connected host.
     pub fn host(&self) -> Option<String> {
-        match self.request.headers.host {
+        let forwarded_host = get_extension_header(&self.request.headers, "X-Forwarded-Host");
+        let direct_host = match self.request.headers.host {
             Some(ref host) => Some(host.http_value()),
             None => None,
-        }
+        };
+        resolve_trusted_optional(self.trusted_proxy, forwarded_host, direct_host)
     }
 
     /// The URL parameters as raw String.
@@ -148,15 +606,22 @@ impl Request {
         self.request.method.http_value()
     }
 
-    /// The remote address of the client.
+    /// The remote address of the client.  Behind a trusted proxy (see
+    /// `set_trusted_proxy`), the first address in `X-Forwarded-For`
+    /// takes precedence over the directly connected peer; the port is
+    /// not known in that case and is reported as `0`.
     pub fn remote_addr(&self) -> Option<SocketAddr> {
-        self.request.remote_addr.clone()
+        let forwarded_for = get_extension_header(&self.request.headers, "X-Forwarded-For");
+        resolve_trusted_remote_addr(self.trusted_proxy, forwarded_for, self.request.remote_addr.clone())
     }
 
-    /// URL scheme (http or https), currently I do not know how to get
-    /// this, the result will always be http.
+    /// URL scheme (http or https).  Behind a trusted proxy (see
+    /// `set_trusted_proxy`), `X-Forwarded-Proto` is consulted; otherwise
+    /// this server never terminates TLS itself, so the result is always
+    /// `http`.
     pub fn scheme(&self) -> String {
-        String::from_str("http")
+        let forwarded_proto = get_extension_header(&self.request.headers, "X-Forwarded-Proto");
+        resolve_trusted_value(self.trusted_proxy, forwarded_proto, String::from_str("http"))
     }
 
     /// Just the host with scheme.
@@ -198,6 +663,36 @@ impl Request {
 }
 
 
+/// Options controlling the attributes of a cookie set via
+/// `Response::set_cookie`.
+#[deriving(Clone)]
+pub struct CookieOptions {
+    pub max_age: Option<int>,
+    pub expires: Option<String>,
+    pub path: Option<String>,
+    pub domain: Option<String>,
+    pub secure: bool,
+    pub http_only: bool,
+    pub same_site: Option<String>,
+}
+
+impl CookieOptions {
+    /// Create a `CookieOptions` with sensible defaults: path `/` and
+    /// every other attribute unset.
+    pub fn new() -> CookieOptions {
+        CookieOptions {
+            max_age: None,
+            expires: None,
+            path: Some(String::from_str("/")),
+            domain: None,
+            secure: false,
+            http_only: false,
+            same_site: None,
+        }
+    }
+}
+
+
 /// Response type.  It is just one container with a couple of parameters
 /// (headers, body, status code etc).
 #[deriving(Clone)]
@@ -261,4 +756,448 @@ impl Response {
     pub fn set_content_length(&mut self, value: uint) {
         self.headers.set("Content-Length", value.to_string().as_slice());
     }
+
+    /// Sets a `Set-Cookie` header with the given name, value and
+    /// attributes.  Unlike the other header setters, this appends to
+    /// any previous `Set-Cookie` headers instead of replacing them, so
+    /// a response can carry several cookies at once.
+    pub fn set_cookie(&mut self, name: &str, value: &str, opts: CookieOptions) {
+        let mut cookie = String::new();
+        cookie.push_str(strip_cookie_attr_chars(name).as_slice());
+        cookie.push_str("=");
+        cookie.push_str(url::encode_component(value).as_slice());
+        match opts.max_age {
+            Some(max_age) => {
+                cookie.push_str("; Max-Age=");
+                cookie.push_str(max_age.to_string().as_slice());
+            },
+            None => (),
+        }
+        match opts.expires {
+            Some(ref expires) => {
+                cookie.push_str("; Expires=");
+                cookie.push_str(strip_cookie_attr_chars(expires.as_slice()).as_slice());
+            },
+            None => (),
+        }
+        match opts.path {
+            Some(ref path) => {
+                cookie.push_str("; Path=");
+                cookie.push_str(strip_cookie_attr_chars(path.as_slice()).as_slice());
+            },
+            None => (),
+        }
+        match opts.domain {
+            Some(ref domain) => {
+                cookie.push_str("; Domain=");
+                cookie.push_str(strip_cookie_attr_chars(domain.as_slice()).as_slice());
+            },
+            None => (),
+        }
+        if opts.secure {
+            cookie.push_str("; Secure");
+        }
+        if opts.http_only {
+            cookie.push_str("; HttpOnly");
+        }
+        match opts.same_site {
+            Some(ref same_site) => {
+                cookie.push_str("; SameSite=");
+                cookie.push_str(strip_cookie_attr_chars(same_site.as_slice()).as_slice());
+            },
+            None => (),
+        }
+        self.headers.add("Set-Cookie", cookie.as_slice());
+    }
+
+    /// Create a redirect response to `location` with the given status
+    /// code (typically 301, 302, 303, 307 or 308).  `\r`/`\n` are
+    /// stripped from `location` to prevent HTTP response splitting.
+    pub fn redirect(location: &str, code: int) -> Response {
+        let mut response = Response::new(String::new());
+        response.status_code = code;
+        response.headers.set("Location", strip_crlf(location).as_slice());
+        response
+    }
+
+    /// Create a response whose body is the serialized JSON value, with
+    /// `Content-Type` set to `application/json`.
+    pub fn with_json(json: Json) -> Response {
+        let mut response = Response::new(json.to_string());
+        response.set_content_type("application/json");
+        response
+    }
+}
+
+
+/// A consuming builder for fluently assembling a `Response` in a
+/// single expression, e.g.
+/// `ResponseBuilder::new().status(201).header("X-Foo", "bar").body(body).build()`.
+pub struct ResponseBuilder {
+    response: Response,
+}
+
+impl ResponseBuilder {
+    /// Start building a response with an empty body.
+    pub fn new() -> ResponseBuilder {
+        ResponseBuilder { response: Response::new(String::new()) }
+    }
+
+    /// Set the status code.
+    pub fn status(mut self, status_code: int) -> ResponseBuilder {
+        self.response.status_code = status_code;
+        self
+    }
+
+    /// Set a header, overwriting any previous value.  `\r`/`\n` are
+    /// stripped from `name` and `value` to prevent HTTP response
+    /// splitting.
+    pub fn header(mut self, name: &str, value: &str) -> ResponseBuilder {
+        self.response.headers.set(strip_crlf(name).as_slice(), strip_crlf(value).as_slice());
+        self
+    }
+
+    /// Set the `Content-Type` header.  `\r`/`\n` are stripped from
+    /// `value` to prevent HTTP response splitting.
+    pub fn content_type(mut self, value: &str) -> ResponseBuilder {
+        self.response.set_content_type(strip_crlf(value).as_slice());
+        self
+    }
+
+    /// Set the response body.  The `Content-Length` header is
+    /// recomputed automatically.
+    pub fn body(mut self, value: String) -> ResponseBuilder {
+        self.response.set_data(value);
+        self
+    }
+
+    /// Finish building and return the assembled `Response`.
+    pub fn build(self) -> Response {
+        self.response
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::io::net::ip::{SocketAddr, IpAddr};
+
+    use http;
+    use http::headers::request::HeaderCollection;
+    use http::server::request::RequestUri::AbsolutePath;
+    use http::method::Get;
+    use serialize::json::Json;
+
+    use config::Config;
+    use super::{CookieOptions, FileStorage, MultiDict, Request, Response, ResponseBuilder,
+                decode_www_form_urlencoded, extract_header_param, parse_cookie_header,
+                parse_json_body, parse_multipart, resolve_trusted_remote_addr,
+                resolve_trusted_value};
+
+    /// Builds a genuine `http::server::Request` carrying the given body
+    /// and raw (unrecognised) headers, so the `Request` glue that reads
+    /// `self.request.headers.extensions` is exercised end-to-end rather
+    /// than only through hand-extracted helper functions.
+    fn test_http_request(body: &str, extensions: Vec<(&str, &str)>) -> http::server::Request {
+        let mut headers = HeaderCollection::new();
+        for &(name, value) in extensions.iter() {
+            headers.extensions.insert(name.to_string(), value.to_string());
+        }
+        http::server::Request {
+            remote_addr: Some(SocketAddr { ip: IpAddr::Ipv4Addr(127, 0, 0, 1), port: 1234 }),
+            headers: headers,
+            method: Get,
+            version: (1, 1),
+            request_uri: AbsolutePath(String::from_str("/")),
+            close_connection: true,
+            body: body.to_string(),
+        }
+    }
+
+    #[test]
+    fn set_cookie_appends_instead_of_overwriting() {
+        let mut response = Response::new(String::new());
+        response.set_cookie("a", "1", CookieOptions::new());
+        response.set_cookie("b", "2", CookieOptions::new());
+        let cookies = response.headers.get_all("Set-Cookie");
+        assert_eq!(cookies.len(), 2);
+        assert!(cookies.iter().any(|c| c.as_slice().starts_with("a=1")));
+        assert!(cookies.iter().any(|c| c.as_slice().starts_with("b=2")));
+    }
+
+    #[test]
+    fn set_cookie_strips_semicolon_and_equals_from_name() {
+        let mut response = Response::new(String::new());
+        response.set_cookie("sid; Domain=evil.com", "1", CookieOptions::new());
+        let cookie = response.headers.get("Set-Cookie").unwrap().clone();
+        assert_eq!(cookie.as_slice(), "sid Domainevil.com=1; Path=/");
+    }
+
+    #[test]
+    fn set_cookie_strips_semicolon_and_equals_from_attributes() {
+        let mut response = Response::new(String::new());
+        let mut opts = CookieOptions::new();
+        opts.domain = Some(String::from_str("evil.com; Secure"));
+        response.set_cookie("sid", "1", opts);
+        let cookie = response.headers.get("Set-Cookie").unwrap().clone();
+        assert_eq!(cookie.as_slice(), "sid=1; Path=/; Domain=evil.com Secure");
+    }
+
+    #[test]
+    fn request_cookies_parses_the_real_cookie_header() {
+        let http_request = test_http_request("", vec![("Cookie", "a=1; b=caf%C3%A9")]);
+        let mut request = Request::new(http_request, &Config::new());
+        assert_eq!(request.cookies().get("a").unwrap().as_slice(), "1");
+        assert_eq!(request.cookies().get("b").unwrap().as_slice(), "café");
+    }
+
+    #[test]
+    fn request_cookies_is_empty_without_a_cookie_header() {
+        let http_request = test_http_request("", vec![]);
+        let mut request = Request::new(http_request, &Config::new());
+        assert!(request.cookies().get("a").is_none());
+    }
+
+    #[test]
+    fn request_host_honours_x_forwarded_host_when_trusted() {
+        let http_request = test_http_request("", vec![("X-Forwarded-Host", "public.example.com")]);
+        let mut request = Request::new(http_request, &Config::new());
+        request.set_trusted_proxy(true);
+        assert_eq!(request.host().unwrap().as_slice(), "public.example.com");
+    }
+
+    #[test]
+    fn request_scheme_honours_x_forwarded_proto_when_trusted() {
+        let http_request = test_http_request("", vec![("X-Forwarded-Proto", "https")]);
+        let mut request = Request::new(http_request, &Config::new());
+        request.set_trusted_proxy(true);
+        assert_eq!(request.scheme().as_slice(), "https");
+        assert!(request.is_secure());
+    }
+
+    #[test]
+    fn request_scheme_ignores_x_forwarded_proto_when_untrusted() {
+        let http_request = test_http_request("", vec![("X-Forwarded-Proto", "https")]);
+        let mut request = Request::new(http_request, &Config::new());
+        assert_eq!(request.scheme().as_slice(), "http");
+        assert!(!request.is_secure());
+    }
+
+    #[test]
+    fn request_remote_addr_honours_x_forwarded_for_when_trusted() {
+        let http_request = test_http_request("", vec![("X-Forwarded-For", "5.6.7.8, 9.9.9.9")]);
+        let mut request = Request::new(http_request, &Config::new());
+        request.set_trusted_proxy(true);
+        let addr = request.remote_addr().unwrap();
+        assert_eq!(addr.ip, IpAddr::Ipv4Addr(5, 6, 7, 8));
+    }
+
+    #[test]
+    fn request_new_resolves_trusted_proxy_from_config() {
+        let http_request = test_http_request("", vec![("X-Forwarded-Proto", "https")]);
+        let mut config = Config::new();
+        config.set("TRUSTED_PROXY", Json::Boolean(true));
+        let mut request = Request::new(http_request, &config);
+        assert_eq!(request.scheme().as_slice(), "https");
+    }
+
+    #[test]
+    fn request_new_defaults_to_untrusted_without_config() {
+        let http_request = test_http_request("", vec![("X-Forwarded-Proto", "https")]);
+        let mut request = Request::new(http_request, &Config::new());
+        assert_eq!(request.scheme().as_slice(), "http");
+    }
+
+    #[test]
+    fn parse_cookie_header_parses_multiple_cookies() {
+        let cookies = parse_cookie_header("a=1; b=2");
+        assert_eq!(cookies.get("a").unwrap().as_slice(), "1");
+        assert_eq!(cookies.get("b").unwrap().as_slice(), "2");
+    }
+
+    #[test]
+    fn parse_cookie_header_percent_decodes_values() {
+        // %C3%A9 is the UTF-8 encoding of e-acute.
+        let cookies = parse_cookie_header("name=caf%C3%A9");
+        assert_eq!(cookies.get("name").unwrap().as_slice(), "café");
+    }
+
+    #[test]
+    fn parse_multipart_ignores_the_closing_boundary() {
+        let mut form = MultiDict::new();
+        let mut files: Vec<(String, FileStorage)> = Vec::new();
+        let body = "--X\r\nContent-Disposition: form-data; name=\"field1\"\r\n\r\nvalue1\r\n--X--\r\n";
+        parse_multipart(body, "X", &mut form, &mut files);
+        assert_eq!(form.get("field1").unwrap().as_slice(), "value1");
+        assert_eq!(files.len(), 0);
+    }
+
+    #[test]
+    fn parse_multipart_strips_exactly_one_trailing_crlf() {
+        let mut form = MultiDict::new();
+        let mut files: Vec<(String, FileStorage)> = Vec::new();
+        let body = "--X\r\nContent-Disposition: form-data; name=\"f\"; filename=\"a.txt\"\r\n\
+                    Content-Type: text/plain\r\n\r\ndata\r\r\n--X--\r\n";
+        parse_multipart(body, "X", &mut form, &mut files);
+        assert_eq!(files.len(), 1);
+        let &(ref name, ref file) = &files[0];
+        assert_eq!(name.as_slice(), "f");
+        assert_eq!(file.data.as_slice(), b"data\r");
+    }
+
+    #[test]
+    fn parse_multipart_empty_filename_is_an_ordinary_form_field() {
+        let mut form = MultiDict::new();
+        let mut files: Vec<(String, FileStorage)> = Vec::new();
+        let body = "--X\r\nContent-Disposition: form-data; name=\"f\"; filename=\"\"\r\n\r\n\r\n--X--\r\n";
+        parse_multipart(body, "X", &mut form, &mut files);
+        assert_eq!(files.len(), 0);
+        assert_eq!(form.get("f").unwrap().as_slice(), "");
+    }
+
+    #[test]
+    fn extract_header_param_keeps_semicolon_inside_quoted_value() {
+        let disposition = "form-data; name=\"f\"; filename=\"a;b.txt\"";
+        assert_eq!(extract_header_param(disposition, "filename").unwrap().as_slice(), "a;b.txt");
+    }
+
+    #[test]
+    fn extract_header_param_skips_unrelated_param_with_multibyte_key() {
+        let disposition = "form-data; abcdé=x; name=\"f\"";
+        assert_eq!(extract_header_param(disposition, "name").unwrap().as_slice(), "f");
+    }
+
+    #[test]
+    fn parse_multipart_keeps_semicolon_inside_quoted_filename() {
+        let mut form = MultiDict::new();
+        let mut files: Vec<(String, FileStorage)> = Vec::new();
+        let body = "--X\r\nContent-Disposition: form-data; name=\"f\"; filename=\"report; final.txt\"\r\n\
+                    Content-Type: text/plain\r\n\r\ndata\r\n--X--\r\n";
+        parse_multipart(body, "X", &mut form, &mut files);
+        assert_eq!(files.len(), 1);
+        let &(_, ref file) = &files[0];
+        assert_eq!(file.filename.as_ref().unwrap().as_slice(), "report; final.txt");
+    }
+
+    #[test]
+    fn decode_www_form_urlencoded_respects_iso_8859_1_charset() {
+        // %E9 is e-acute in ISO-8859-1.
+        let pairs = decode_www_form_urlencoded("name=caf%E9", "iso-8859-1");
+        assert_eq!(pairs.len(), 1);
+        let &(ref key, ref value) = &pairs[0];
+        assert_eq!(key.as_slice(), "name");
+        assert_eq!(value.as_slice(), "café");
+    }
+
+    #[test]
+    fn decode_www_form_urlencoded_falls_back_to_utf8_for_unknown_charset() {
+        // %C3%A9 is the UTF-8 encoding of e-acute.
+        let pairs = decode_www_form_urlencoded("name=caf%C3%A9", "not-a-real-charset");
+        let &(_, ref value) = &pairs[0];
+        assert_eq!(value.as_slice(), "café");
+    }
+
+    #[test]
+    fn parse_json_body_ignores_non_json_content_type() {
+        assert!(parse_json_body(false, "{\"a\": 1}").is_none());
+    }
+
+    #[test]
+    fn parse_json_body_rejects_malformed_json() {
+        assert!(parse_json_body(true, "not json").is_none());
+    }
+
+    #[test]
+    fn parse_json_body_parses_a_valid_body() {
+        assert!(parse_json_body(true, "{\"a\": 1}").is_some());
+    }
+
+    #[test]
+    fn resolve_trusted_value_ignores_forwarded_header_when_untrusted() {
+        let scheme = resolve_trusted_value(false, Some("https"), String::from_str("http"));
+        assert_eq!(scheme.as_slice(), "http");
+    }
+
+    #[test]
+    fn resolve_trusted_value_honours_forwarded_header_when_trusted() {
+        let scheme = resolve_trusted_value(true, Some("https"), String::from_str("http"));
+        assert_eq!(scheme.as_slice(), "https");
+    }
+
+    #[test]
+    fn resolve_trusted_remote_addr_ignores_forwarded_for_when_untrusted() {
+        let direct = Some(SocketAddr { ip: IpAddr::Ipv4Addr(127, 0, 0, 1), port: 1234 });
+        let result = resolve_trusted_remote_addr(false, Some("1.2.3.4"), direct);
+        assert_eq!(result, Some(SocketAddr { ip: IpAddr::Ipv4Addr(127, 0, 0, 1), port: 1234 }));
+    }
+
+    #[test]
+    fn resolve_trusted_remote_addr_honours_forwarded_for_when_trusted() {
+        let direct = Some(SocketAddr { ip: IpAddr::Ipv4Addr(127, 0, 0, 1), port: 1234 });
+        let result = resolve_trusted_remote_addr(true, Some("1.2.3.4, 5.6.7.8"), direct);
+        assert_eq!(result, Some(SocketAddr { ip: IpAddr::Ipv4Addr(1, 2, 3, 4), port: 0 }));
+    }
+
+    #[test]
+    fn redirect_sets_status_and_location() {
+        let response = Response::redirect("/next", 302);
+        assert_eq!(response.status_code, 302);
+        assert_eq!(response.headers.get("Location").unwrap().as_slice(), "/next");
+    }
+
+    #[test]
+    fn redirect_strips_crlf_from_location() {
+        let response = Response::redirect("/next\r\nX-Injected: evil", 302);
+        assert_eq!(response.headers.get("Location").unwrap().as_slice(), "/nextX-Injected: evil");
+    }
+
+    #[test]
+    fn with_json_sets_content_type_and_length() {
+        let json: Json = from_str("{\"a\": 1}").unwrap();
+        let body = json.to_string();
+        let response = Response::with_json(json);
+        assert_eq!(response.content_type().unwrap().as_slice(), "application/json; charset=utf-8");
+        assert_eq!(response.content_length().unwrap(), body.len());
+        assert_eq!(response.body, body);
+    }
+
+    #[test]
+    fn response_builder_produces_expected_response() {
+        let response = ResponseBuilder::new()
+            .status(201)
+            .header("X-Foo", "bar")
+            .content_type("text/plain")
+            .body(String::from_str("hello"))
+            .build();
+        assert_eq!(response.status_code, 201);
+        assert_eq!(response.headers.get("X-Foo").unwrap().as_slice(), "bar");
+        assert_eq!(response.content_type().unwrap().as_slice(), "text/plain; charset=utf-8");
+        assert_eq!(response.body, String::from_str("hello"));
+        assert_eq!(response.content_length().unwrap(), 5);
+    }
+
+    #[test]
+    fn response_builder_header_strips_crlf() {
+        let response = ResponseBuilder::new()
+            .header("X-Foo", "bar\r\nX-Injected: evil")
+            .build();
+        assert_eq!(response.headers.get("X-Foo").unwrap().as_slice(), "barX-Injected: evil");
+    }
+
+    #[test]
+    fn response_builder_header_strips_crlf_from_name() {
+        let response = ResponseBuilder::new()
+            .header("X-Foo\r\nX-Injected: evil", "bar")
+            .build();
+        assert_eq!(response.headers.get("X-FooX-Injected: evil").unwrap().as_slice(), "bar");
+    }
+
+    #[test]
+    fn response_builder_content_type_strips_crlf() {
+        let response = ResponseBuilder::new()
+            .content_type("text/plain\r\nX-Injected: evil")
+            .build();
+        assert_eq!(response.content_type().unwrap().as_slice(),
+                   "text/plainX-Injected: evil; charset=utf-8");
+    }
 }